@@ -18,14 +18,18 @@ impl Error {
     /// Returns the path where the error occured if it applies,
     /// for instance during IO operations.
     pub fn path(&self) -> Option<&Path> {
-        let InnerError::Io { ref path, .. } = self.0;
-        Some(path)
+        match &self.0 {
+            InnerError::Io { path, .. } => Some(path),
+            InnerError::Loop { child, .. } => Some(child),
+        }
     }
 
     /// Returns the original [`io::Error`] if any.
     pub fn io(&self) -> Option<&io::Error> {
-        let InnerError::Io { ref source, .. } = self.0;
-        Some(source)
+        match &self.0 {
+            InnerError::Io { source, .. } => Some(source),
+            InnerError::Loop { .. } => None,
+        }
     }
 }
 
@@ -39,4 +43,13 @@ pub enum InnerError {
         /// The IO error.
         source: io::Error,
     },
+
+    #[error("symlink loop detected: '{child}' already visited as '{ancestor}'")]
+    /// A followed symlink points back to one of its own ancestor directories.
+    Loop {
+        /// The ancestor directory the symlink points back to.
+        ancestor: PathBuf,
+        /// The symlink that closes the loop.
+        child: PathBuf,
+    },
 }
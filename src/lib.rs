@@ -44,23 +44,197 @@
 #![forbid(unsafe_code)]
 #![deny(missing_docs)]
 
+mod error;
+
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+use std::fs::{FileType, Metadata};
 use std::path::{Path, PathBuf};
 use std::pin::Pin;
+use std::sync::Arc;
 use std::task::{Context, Poll};
 
-use async_fs::{read_dir, ReadDir};
 use futures_lite::future::{Boxed as BoxedFut, FutureExt};
 use futures_lite::stream::{self, Stream, StreamExt};
 
-#[doc(no_inline)]
-pub use async_fs::DirEntry;
-#[doc(no_inline)]
-pub use std::io::Result;
+use error::InnerError;
+
+pub use error::Error;
+
+/// The result of a fallible operation on a [`WalkDir`] stream.
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// The number of directory entries read per blocking-pool round-trip when
+/// [`WalkDir::buffer`] is left at its default.
+const DEFAULT_BUFFER: usize = 32;
+
+/// The range [`WalkDir::buffer`] clamps its argument to. Below the minimum a
+/// batch can come back empty even though the directory isn't, which would
+/// make the stream stop early; above the maximum, a single eagerly allocated
+/// batch buffer could exhaust memory outright.
+const MIN_BUFFER: usize = 1;
+const MAX_BUFFER: usize = 1024;
+
+fn io_err(source: std::io::Error, path: impl Into<PathBuf>) -> Error {
+    InnerError::Io {
+        path: path.into(),
+        source,
+    }
+    .into()
+}
+
+type BoxStream = futures_lite::stream::Boxed<Result<Entry>>;
+type BoxDirStream = futures_lite::stream::Boxed<std::io::Result<DirEntry>>;
+
+/// Abstracts the filesystem operations a traversal needs, so a [`WalkDir`]
+/// can walk something other than the real, local filesystem — an in-memory
+/// tree in tests, or a virtual or remote store.
+///
+/// The real filesystem is exposed through [`StdFs`], which [`WalkDir::new`]
+/// uses by default; pass a custom backend with [`WalkDir::new_with_fs`].
+pub trait FileSystem: Send + Sync + 'static {
+    /// Opens `path` for reading its entries as a stream, reading up to
+    /// `buffer_size` entries per underlying round-trip.
+    fn read_dir(
+        &self,
+        path: PathBuf,
+        buffer_size: usize,
+    ) -> BoxedFut<std::io::Result<BoxDirStream>>;
+
+    /// Returns the metadata for `path`, following symbolic links.
+    fn metadata(&self, path: PathBuf) -> BoxedFut<std::io::Result<Metadata>>;
+
+    /// Returns the canonical, absolute form of `path` with all symbolic
+    /// links resolved.
+    fn canonicalize(&self, path: PathBuf) -> BoxedFut<std::io::Result<PathBuf>>;
+}
+
+/// Resolves a [`DirEntry`]'s own path and metadata, independently of the
+/// backend that produced it.
+///
+/// Implement this to back a [`FileSystem::read_dir`] stream with entries of
+/// your own making; wrap the implementation in a [`DirEntry`] with
+/// [`DirEntry::new`].
+pub trait DirEntryOps: Send + Sync + 'static {
+    /// Returns the full path to this entry.
+    fn path(&self) -> PathBuf;
+    /// Returns the bare name of this entry without the leading path.
+    fn file_name(&self) -> std::ffi::OsString;
+    /// Returns the metadata for the file this entry points to.
+    fn metadata(&self) -> BoxedFut<std::io::Result<Metadata>>;
+    /// Returns the file type for the file this entry points to.
+    fn file_type(&self) -> BoxedFut<std::io::Result<FileType>>;
+}
+
+/// A directory entry yielded while reading a directory, produced by a
+/// [`FileSystem`] backend.
+///
+/// Its [`metadata`](DirEntry::metadata) and [`file_type`](DirEntry::file_type)
+/// accessors are async so backends that need IO to resolve them can run it
+/// off the calling task.
+#[derive(Clone)]
+pub struct DirEntry(Arc<dyn DirEntryOps>);
+
+impl DirEntry {
+    /// Wraps a custom [`DirEntryOps`] implementation into a `DirEntry`, so a
+    /// [`FileSystem`] backend other than [`StdFs`] can produce entries of
+    /// its own making.
+    pub fn new(ops: impl DirEntryOps) -> Self {
+        Self(Arc::new(ops))
+    }
+
+    /// Returns the full path to this entry.
+    pub fn path(&self) -> PathBuf {
+        self.0.path()
+    }
+
+    /// Returns the metadata for the file this entry points to.
+    ///
+    /// This does not traverse symbolic links.
+    pub async fn metadata(&self) -> std::io::Result<Metadata> {
+        self.0.metadata().await
+    }
+
+    /// Returns the file type for the file this entry points to.
+    ///
+    /// This does not traverse symbolic links.
+    pub async fn file_type(&self) -> std::io::Result<FileType> {
+        self.0.file_type().await
+    }
+
+    /// Returns the bare name of this entry without the leading path.
+    pub fn file_name(&self) -> std::ffi::OsString {
+        self.0.file_name()
+    }
+}
+
+impl std::fmt::Debug for DirEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DirEntry")
+            .field("path", &self.path())
+            .finish()
+    }
+}
+
+/// The default [`FileSystem`] backend, reading from the real, local
+/// filesystem through [`async_fs`] and the blocking thread pool.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StdFs;
 
-type BoxStream = futures_lite::stream::Boxed<Result<DirEntry>>;
+impl FileSystem for StdFs {
+    fn read_dir(
+        &self,
+        path: PathBuf,
+        buffer_size: usize,
+    ) -> BoxedFut<std::io::Result<BoxDirStream>> {
+        async move {
+            let read_dir = blocking::unblock(move || std::fs::read_dir(path)).await?;
+            let batched = BatchedReadDir::new(read_dir, buffer_size);
+            Ok(stream::unfold(batched, |mut read_dir| async move {
+                read_dir.next().await.map(|entry| (entry, read_dir))
+            })
+            .boxed())
+        }
+        .boxed()
+    }
+
+    fn metadata(&self, path: PathBuf) -> BoxedFut<std::io::Result<Metadata>> {
+        async move { async_fs::metadata(path).await }.boxed()
+    }
+
+    fn canonicalize(&self, path: PathBuf) -> BoxedFut<std::io::Result<PathBuf>> {
+        async move { async_fs::canonicalize(path).await }.boxed()
+    }
+}
 
-/// A `Stream` of `DirEntry` generated from recursively traversing
-/// a directory.
+/// A [`DirEntryOps`] implementation backed by a real [`std::fs::DirEntry`].
+struct StdDirEntry(Arc<std::fs::DirEntry>);
+
+impl DirEntryOps for StdDirEntry {
+    fn path(&self) -> PathBuf {
+        self.0.path()
+    }
+
+    fn file_name(&self) -> std::ffi::OsString {
+        self.0.file_name()
+    }
+
+    fn metadata(&self) -> BoxedFut<std::io::Result<Metadata>> {
+        let entry = self.0.clone();
+        blocking::unblock(move || entry.metadata()).boxed()
+    }
+
+    fn file_type(&self) -> BoxedFut<std::io::Result<FileType>> {
+        let entry = self.0.clone();
+        blocking::unblock(move || entry.file_type()).boxed()
+    }
+}
+
+fn std_dir_entry(entry: std::fs::DirEntry) -> DirEntry {
+    DirEntry::new(StdDirEntry(Arc::new(entry)))
+}
+
+/// A `Stream` of `Entry` generated from recursively traversing a directory.
 ///
 /// Entries are returned without a specific ordering. The top most root directory
 /// is not returned but child directories are.
@@ -69,88 +243,569 @@ type BoxStream = futures_lite::stream::Boxed<Result<DirEntry>>;
 ///
 /// Panics if the directories depth overflows `usize`.
 pub struct WalkDir {
-    entries: BoxStream,
+    root: PathBuf,
+    min_depth: usize,
+    max_depth: usize,
+    filter: Option<Filter>,
+    follow_links: bool,
+    sort_by: Option<Comparator>,
+    contents_first: bool,
+    buffer: usize,
+    same_file_system: bool,
+    fs: Arc<dyn FileSystem>,
+    entries: Option<BoxStream>,
 }
 
 impl WalkDir {
-    /// Returns a new `Walkdir` starting at `root`.
+    /// Returns a new `WalkDir` starting at `root`, reading from the real,
+    /// local filesystem.
     pub fn new(root: impl AsRef<Path>) -> Self {
+        Self::new_with_fs(root, StdFs)
+    }
+
+    /// Returns a new `WalkDir` starting at `root`, reading through a custom
+    /// [`FileSystem`] backend instead of the real filesystem.
+    pub fn new_with_fs(root: impl AsRef<Path>, fs: impl FileSystem) -> Self {
         Self {
-            entries: walk_dir(root),
+            root: root.as_ref().to_owned(),
+            min_depth: 0,
+            max_depth: usize::MAX,
+            filter: None,
+            follow_links: false,
+            sort_by: None,
+            contents_first: false,
+            buffer: DEFAULT_BUFFER,
+            same_file_system: false,
+            fs: Arc::new(fs),
+            entries: None,
         }
     }
+
+    /// Sets the minimum depth of entries yielded by this stream.
+    ///
+    /// Entries shallower than `min_depth` are still walked but not yielded. The
+    /// root directory itself is at depth 0.
+    pub fn min_depth(mut self, min_depth: usize) -> Self {
+        self.min_depth = min_depth;
+        self
+    }
+
+    /// Sets the maximum depth of entries yielded by this stream.
+    ///
+    /// Directories at `max_depth` are yielded but not descended into.
+    /// The root directory itself is at depth 0.
+    pub fn max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// Sets a predicate to filter entries and prune directories during the walk.
+    ///
+    /// The predicate is consulted before a directory is opened, so returning
+    /// [`Filtering::IgnoreDir`] for a directory entry skips descending into it
+    /// entirely instead of paying the cost of enumerating and discarding its
+    /// contents.
+    pub fn filter_entry(
+        mut self,
+        filter: impl FnMut(&DirEntry) -> Filtering + Send + 'static,
+    ) -> Self {
+        self.filter = Some(Box::new(filter));
+        self
+    }
+
+    /// Sets whether symbolic links to directories are followed.
+    ///
+    /// Defaults to `false`. When enabled, each followed directory is checked
+    /// against the stack of its currently open ancestors, and a loop error is
+    /// reported through [`Error`] instead of recursing forever if a cycle is
+    /// found.
+    pub fn follow_links(mut self, follow_links: bool) -> Self {
+        self.follow_links = follow_links;
+        self
+    }
+
+    /// Sorts each directory's entries with `cmp` before yielding them.
+    ///
+    /// This requires buffering a whole directory level in memory, so the
+    /// default unsorted fast path is kept when no comparator is set.
+    pub fn sort_by(
+        mut self,
+        cmp: impl FnMut(&DirEntry, &DirEntry) -> Ordering + Send + 'static,
+    ) -> Self {
+        self.sort_by = Some(Box::new(cmp));
+        self
+    }
+
+    /// Sets whether a directory's contents are yielded before the directory
+    /// entry itself.
+    ///
+    /// Defaults to `false`, which yields a directory as soon as it is found.
+    pub fn contents_first(mut self, contents_first: bool) -> Self {
+        self.contents_first = contents_first;
+        self
+    }
+
+    /// Sets the number of directory entries read per blocking-pool
+    /// round-trip.
+    ///
+    /// Defaults to 32 entries. Reading in batches amortizes
+    /// the cost of hopping onto the blocking thread pool over many entries
+    /// instead of paying it once per entry, which matters for directories
+    /// with thousands of files. This is purely a performance knob: it does
+    /// not change which entries are yielded.
+    ///
+    /// Clamped to between 1 and 1024 entries: 0 would make every read
+    /// report an empty directory, and an unbounded value would let a single
+    /// batch's up-front allocation exhaust memory.
+    pub fn buffer(mut self, buffer: usize) -> Self {
+        self.buffer = buffer.clamp(MIN_BUFFER, MAX_BUFFER);
+        self
+    }
+
+    /// Sets whether the traversal stays on the filesystem it started on.
+    ///
+    /// Defaults to `false`. When enabled, a subdirectory whose device id
+    /// differs from the root's is yielded but not descended into, which
+    /// keeps a walk from wandering onto network mounts, `/proc`, or other
+    /// filesystems mounted underneath the root. This is a no-op on
+    /// platforms without device ids.
+    pub fn same_file_system(mut self, same_file_system: bool) -> Self {
+        self.same_file_system = same_file_system;
+        self
+    }
 }
 
 impl Stream for WalkDir {
-    type Item = Result<DirEntry>;
+    type Item = Result<Entry>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        let entries = Pin::new(&mut self.entries);
+        if self.entries.is_none() {
+            let walker = Walker {
+                min_depth: self.min_depth,
+                max_depth: self.max_depth,
+                filter: self.filter.take(),
+                follow_links: self.follow_links,
+                sort_by: self.sort_by.take(),
+                contents_first: self.contents_first,
+                buffer_size: self.buffer,
+                same_file_system: self.same_file_system,
+                root_dev: None,
+                fs: self.fs.clone(),
+            };
+            self.entries = Some(walk_dir(self.root.clone(), walker));
+        }
+        let entries = Pin::new(self.entries.as_mut().expect("entries stream is set above"));
         entries.poll_next(cx)
     }
 }
 
-fn walk_dir(root: impl AsRef<Path>) -> BoxStream {
-    stream::unfold(State::Start(root.as_ref().to_owned()), |state| async move {
-        match state {
-            State::Start(root) => match read_dir(root).await {
-                Err(e) => return Some((Err(e), State::Done)),
-                Ok(rd) => return walk(vec![rd]).await,
-            },
-            State::Walk(dirs) => return walk(dirs).await,
-            State::Done => return None,
-        }
-    })
+/// The result of a [`WalkDir::filter_entry`] predicate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Filtering {
+    /// Keep the entry and, if it is a directory, descend into it.
+    Continue,
+    /// Skip the entry itself, but still descend into it if it is a directory.
+    Ignore,
+    /// Skip the entry and, if it is a directory, do not descend into it.
+    IgnoreDir,
+}
+
+type Filter = Box<dyn FnMut(&DirEntry) -> Filtering + Send>;
+type Comparator = Box<dyn FnMut(&DirEntry, &DirEntry) -> Ordering + Send>;
+
+struct Walker {
+    min_depth: usize,
+    max_depth: usize,
+    filter: Option<Filter>,
+    follow_links: bool,
+    sort_by: Option<Comparator>,
+    contents_first: bool,
+    buffer_size: usize,
+    same_file_system: bool,
+    /// The root's device id, captured once the walk starts when
+    /// `same_file_system` is set; `None` on platforms without device ids.
+    root_dev: Option<u64>,
+    fs: Arc<dyn FileSystem>,
+}
+
+/// A directory entry yielded from a [`WalkDir`] stream, tracking the depth at
+/// which it was found.
+#[derive(Debug)]
+pub struct Entry {
+    dir_entry: DirEntry,
+    depth: usize,
+}
+
+impl Entry {
+    fn new(dir_entry: DirEntry, depth: usize) -> Self {
+        Self { dir_entry, depth }
+    }
+
+    /// Returns the full path to this entry.
+    pub fn path(&self) -> PathBuf {
+        self.dir_entry.path()
+    }
+
+    /// Returns the metadata for the file this entry points to.
+    pub async fn metadata(&self) -> Result<Metadata> {
+        self.dir_entry
+            .metadata()
+            .await
+            .map_err(|e| io_err(e, self.path()))
+    }
+
+    /// Returns the file type for the file this entry points to.
+    pub async fn file_type(&self) -> Result<FileType> {
+        self.dir_entry
+            .file_type()
+            .await
+            .map_err(|e| io_err(e, self.path()))
+    }
+
+    /// Returns the bare name of this entry without the leading path.
+    pub fn file_name(&self) -> std::ffi::OsString {
+        self.dir_entry.file_name()
+    }
+
+    /// Returns the depth at which this entry was found relative to the root
+    /// of the walk, which is at depth 0.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+}
+
+fn walk_dir(root: PathBuf, walker: Walker) -> BoxStream {
+    stream::unfold(
+        (walker, State::Start(root)),
+        |(mut walker, state)| async move {
+            match state {
+                State::Start(root) => {
+                    let metadata = if walker.follow_links || walker.same_file_system {
+                        match walker.fs.metadata(root.clone()).await {
+                            Err(e) => return Some((Err(io_err(e, &root)), (walker, State::Done))),
+                            Ok(metadata) => Some(metadata),
+                        }
+                    } else {
+                        None
+                    };
+                    if walker.same_file_system {
+                        walker.root_dev = metadata.as_ref().and_then(dev_of);
+                    }
+                    let id = if walker.follow_links {
+                        let metadata = metadata.as_ref().expect("fetched above");
+                        match dir_identity(&root, metadata, &walker.fs).await {
+                            Err(e) => return Some((Err(e), (walker, State::Done))),
+                            Ok(id) => Some(id),
+                        }
+                    } else {
+                        None
+                    };
+                    let fs = walker.fs.clone();
+                    match open_level(root, id, walker.sort_by.as_mut(), walker.buffer_size, &fs)
+                        .await
+                    {
+                        Err(e) => Some((Err(e), (walker, State::Done))),
+                        Ok(level) => walk(walker, vec![level]).await,
+                    }
+                }
+                State::Walk(dirs) => walk(walker, dirs).await,
+                State::Done => None,
+            }
+        },
+    )
     .boxed()
 }
 
 enum State {
     Start(PathBuf),
-    Walk(Vec<ReadDir>),
+    Walk(Vec<Level>),
     Done,
 }
 
-type UnfoldState = Option<(Result<DirEntry>, State)>;
+/// A currently open directory, tracked so descendants can find their way
+/// back to the top and, when following links, detect cycles.
+struct Level {
+    path: PathBuf,
+    entries: EntrySource,
+    id: Option<DirId>,
+    /// The directory's own entry, deferred here until its contents have been
+    /// fully yielded, when `contents_first` is set.
+    pending_self: Option<Entry>,
+}
+
+impl Level {
+    async fn next_entry(&mut self) -> Option<std::io::Result<DirEntry>> {
+        match &mut self.entries {
+            EntrySource::Batched(read_dir) => read_dir.next().await,
+            EntrySource::Sorted(entries) => entries.next().map(Ok),
+        }
+    }
+}
 
-fn walk(mut dirs: Vec<ReadDir>) -> BoxedFut<UnfoldState> {
+enum EntrySource {
+    Batched(BoxDirStream),
+    Sorted(std::vec::IntoIter<DirEntry>),
+}
+
+/// Reads a [`std::fs::ReadDir`] on the blocking thread pool in batches of up
+/// to `buffer_size` entries, instead of hopping onto the pool once per entry.
+struct BatchedReadDir {
+    inner: Option<std::fs::ReadDir>,
+    buffer_size: usize,
+    buffered: VecDeque<std::io::Result<DirEntry>>,
+}
+
+impl BatchedReadDir {
+    fn new(read_dir: std::fs::ReadDir, buffer_size: usize) -> Self {
+        Self {
+            inner: Some(read_dir),
+            buffer_size,
+            buffered: VecDeque::new(),
+        }
+    }
+
+    async fn next(&mut self) -> Option<std::io::Result<DirEntry>> {
+        if self.buffered.is_empty() {
+            let mut read_dir = self.inner.take()?;
+            let buffer_size = self.buffer_size;
+            let (read_dir, batch) = blocking::unblock(move || {
+                let mut batch = VecDeque::with_capacity(buffer_size);
+                for _ in 0..buffer_size {
+                    match read_dir.next() {
+                        Some(entry) => batch.push_back(entry.map(std_dir_entry)),
+                        None => break,
+                    }
+                }
+                (read_dir, batch)
+            })
+            .await;
+            if batch.len() == buffer_size {
+                self.inner = Some(read_dir);
+            }
+            self.buffered = batch;
+        }
+        self.buffered.pop_front()
+    }
+}
+
+async fn open_level(
+    path: PathBuf,
+    id: Option<DirId>,
+    sort_by: Option<&mut Comparator>,
+    buffer_size: usize,
+    fs: &Arc<dyn FileSystem>,
+) -> Result<Level> {
+    let mut read_dir = fs
+        .read_dir(path.clone(), buffer_size)
+        .await
+        .map_err(|e| io_err(e, &path))?;
+    let entries = match sort_by {
+        None => EntrySource::Batched(read_dir),
+        Some(cmp) => {
+            let mut buffered = Vec::new();
+            while let Some(entry) = read_dir.next().await {
+                buffered.push(entry.map_err(|e| io_err(e, &path))?);
+            }
+            buffered.sort_by(|a, b| cmp(a, b));
+            EntrySource::Sorted(buffered.into_iter())
+        }
+    };
+    Ok(Level {
+        path,
+        entries,
+        id,
+        pending_self: None,
+    })
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum DirId {
+    #[cfg(unix)]
+    DevIno(u64, u64),
+    #[cfg(not(unix))]
+    Canonical(PathBuf),
+}
+
+#[cfg(unix)]
+async fn dir_identity(
+    _path: &Path,
+    metadata: &Metadata,
+    _fs: &Arc<dyn FileSystem>,
+) -> Result<DirId> {
+    use std::os::unix::fs::MetadataExt;
+    Ok(DirId::DevIno(metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+async fn dir_identity(
+    path: &Path,
+    _metadata: &Metadata,
+    fs: &Arc<dyn FileSystem>,
+) -> Result<DirId> {
+    let canonical = fs
+        .canonicalize(path.to_owned())
+        .await
+        .map_err(|e| io_err(e, path))?;
+    Ok(DirId::Canonical(canonical))
+}
+
+/// Returns the device id `metadata` resides on, used by `same_file_system`
+/// to detect mount-point crossings. Always `None` on platforms without
+/// device ids, where `same_file_system` is a no-op.
+#[cfg(unix)]
+fn dev_of(metadata: &Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Some(metadata.dev())
+}
+
+#[cfg(not(unix))]
+fn dev_of(_metadata: &Metadata) -> Option<u64> {
+    None
+}
+
+fn find_loop_ancestor<'a>(levels: &'a [Level], id: &DirId) -> Option<&'a PathBuf> {
+    levels
+        .iter()
+        .find(|level| level.id.as_ref() == Some(id))
+        .map(|level| &level.path)
+}
+
+type UnfoldState = Option<(Result<Entry>, (Walker, State))>;
+
+/// The outcome of handling one entry: either a result ready to yield, or a
+/// signal to keep looping for the next entry without growing the stack.
+enum Step {
+    Yield(Result<Entry>),
+    Continue,
+}
+
+/// Drives the walk forward until an entry is ready to yield or the traversal
+/// is done, looping instead of recursing so that a long run of skipped
+/// entries (e.g. below `min_depth`, or pruned by `filter_entry`) can't
+/// overflow the stack.
+fn walk(mut walker: Walker, mut dirs: Vec<Level>) -> BoxedFut<UnfoldState> {
     async move {
-        if let Some(dir) = dirs.last_mut() {
-            match dir.next().await {
-                Some(Ok(entry)) => walk_entry(entry, dirs).await,
-                Some(Err(e)) => Some((Err(e), State::Walk(dirs))),
+        loop {
+            let depth = dirs.len();
+            let level = dirs.last_mut()?;
+            match level.next_entry().await {
+                Some(Ok(entry)) => match walk_entry(&mut walker, entry, &mut dirs, depth).await {
+                    Step::Yield(result) => return Some((result, (walker, State::Walk(dirs)))),
+                    Step::Continue => {}
+                },
+                Some(Err(e)) => {
+                    let path = level.path.clone();
+                    return Some((Err(io_err(e, path)), (walker, State::Walk(dirs))));
+                }
                 None => {
-                    dirs.pop();
-                    walk(dirs).await
+                    let finished = dirs.pop();
+                    if let Some(entry) = finished.and_then(|level| level.pending_self) {
+                        return Some((Ok(entry), (walker, State::Walk(dirs))));
+                    }
                 }
             }
-        } else {
-            None
         }
     }
     .boxed()
 }
 
-async fn walk_entry(entry: DirEntry, mut dirs: Vec<ReadDir>) -> UnfoldState {
-    match entry.file_type().await {
-        Err(e) => Some((Err(e), State::Walk(dirs))),
-        Ok(ft) if ft.is_dir() => {
-            let rd = match read_dir(entry.path()).await {
-                Err(e) => return Some((Err(e), State::Done)),
-                Ok(rd) => rd,
-            };
-            dirs.push(rd);
-            Some((Ok(entry), State::Walk(dirs)))
+async fn walk_entry(
+    walker: &mut Walker,
+    entry: DirEntry,
+    dirs: &mut Vec<Level>,
+    depth: usize,
+) -> Step {
+    let is_dir;
+    let mut id = None;
+    let mut dev = None;
+    if walker.follow_links {
+        match walker.fs.metadata(entry.path()).await {
+            Err(e) => return Step::Yield(Err(io_err(e, entry.path()))),
+            Ok(metadata) => {
+                is_dir = metadata.is_dir();
+                dev = dev_of(&metadata);
+                if is_dir {
+                    match dir_identity(&entry.path(), &metadata, &walker.fs).await {
+                        Err(e) => return Step::Yield(Err(e)),
+                        Ok(dir_id) => id = Some(dir_id),
+                    }
+                }
+            }
+        }
+    } else {
+        match entry.file_type().await {
+            Err(e) => return Step::Yield(Err(io_err(e, entry.path()))),
+            Ok(ft) => is_dir = ft.is_dir(),
+        }
+        if walker.same_file_system && is_dir {
+            match entry.metadata().await {
+                Err(e) => return Step::Yield(Err(io_err(e, entry.path()))),
+                Ok(metadata) => dev = dev_of(&metadata),
+            }
+        }
+    }
+
+    let filtering = walker
+        .filter
+        .as_mut()
+        .map_or(Filtering::Continue, |f| f(&entry));
+    let should_yield = filtering == Filtering::Continue
+        && depth >= walker.min_depth
+        && depth <= walker.max_depth;
+    let same_file_system =
+        !walker.same_file_system || walker.root_dev.is_none() || dev == walker.root_dev;
+
+    if is_dir && filtering != Filtering::IgnoreDir && depth < walker.max_depth && same_file_system {
+        if let Some(ancestor) = id.as_ref().and_then(|id| find_loop_ancestor(dirs, id)) {
+            let err = InnerError::Loop {
+                ancestor: ancestor.clone(),
+                child: entry.path(),
+            }
+            .into();
+            return Step::Yield(Err(err));
+        }
+        let fs = walker.fs.clone();
+        let mut level = match open_level(
+            entry.path(),
+            id,
+            walker.sort_by.as_mut(),
+            walker.buffer_size,
+            &fs,
+        )
+        .await
+        {
+            Err(e) => return Step::Yield(Err(e)),
+            Ok(level) => level,
+        };
+        if walker.contents_first && should_yield {
+            level.pending_self = Some(Entry::new(entry, depth));
+            dirs.push(level);
+            return Step::Continue;
         }
-        Ok(_) => Some((Ok(entry), State::Walk(dirs))),
+        dirs.push(level);
+    }
+
+    if should_yield {
+        Step::Yield(Ok(Entry::new(entry, depth)))
+    } else {
+        Step::Continue
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::WalkDir;
-    use futures_lite::future::block_on;
+    use super::{
+        BoxDirStream, DirEntry, DirEntryOps, FileSystem, FileType, Filtering, Metadata, StdFs,
+        WalkDir,
+    };
+    use futures_lite::future::{block_on, Boxed as BoxedFut, FutureExt};
     use futures_lite::stream::StreamExt;
     use std::io::{ErrorKind, Result};
+    use std::path::PathBuf;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
 
     #[test]
     fn walk_dir_empty() -> Result<()> {
@@ -168,7 +823,7 @@ mod tests {
             let mut wd = WalkDir::new("foobar");
             match wd.next().await.unwrap() {
                 Ok(_) => panic!("want error"),
-                Err(e) => assert_eq!(e.kind(), ErrorKind::NotFound),
+                Err(e) => assert_eq!(e.io().unwrap().kind(), ErrorKind::NotFound),
             }
         })
     }
@@ -208,4 +863,501 @@ mod tests {
             Ok(())
         })
     }
+
+    #[test]
+    fn walk_dir_min_depth() -> Result<()> {
+        block_on(async {
+            let root = tempfile::tempdir()?;
+            let f1 = root.path().join("f1.txt");
+            let d1 = root.path().join("d1");
+            let f2 = d1.join("f2.txt");
+
+            async_fs::create_dir_all(&d1).await?;
+            async_fs::write(&f1, []).await?;
+            async_fs::write(&f2, []).await?;
+
+            let mut wd = WalkDir::new(root.path()).min_depth(2);
+
+            let mut got = Vec::new();
+            while let Some(entry) = wd.next().await {
+                let entry = entry.unwrap();
+                got.push(entry.path());
+            }
+            got.sort();
+            assert_eq!(got, vec![f2]);
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn walk_dir_max_depth() -> Result<()> {
+        block_on(async {
+            let root = tempfile::tempdir()?;
+            let f1 = root.path().join("f1.txt");
+            let d1 = root.path().join("d1");
+            let f2 = d1.join("f2.txt");
+            let d2 = d1.join("d2");
+            let f3 = d2.join("f3.txt");
+
+            async_fs::create_dir_all(&d2).await?;
+            async_fs::write(&f1, []).await?;
+            async_fs::write(&f2, []).await?;
+            async_fs::write(&f3, []).await?;
+
+            let mut wd = WalkDir::new(root.path()).max_depth(1);
+
+            let mut got = Vec::new();
+            while let Some(entry) = wd.next().await {
+                let entry = entry.unwrap();
+                got.push(entry.path());
+            }
+            got.sort();
+            assert_eq!(got, vec![d1, f1]);
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn walk_dir_filter_entry() -> Result<()> {
+        block_on(async {
+            let root = tempfile::tempdir()?;
+            let f1 = root.path().join("f1.txt");
+            let d1 = root.path().join("d1");
+            let f2 = d1.join("f2.txt");
+            let d2 = root.path().join("d2");
+            let f3 = d2.join("f3.txt");
+
+            async_fs::create_dir_all(&d1).await?;
+            async_fs::create_dir_all(&d2).await?;
+            async_fs::write(&f1, []).await?;
+            async_fs::write(&f2, []).await?;
+            async_fs::write(&f3, []).await?;
+
+            let mut wd = WalkDir::new(root.path()).filter_entry(|entry| {
+                if entry.file_name() == "d2" {
+                    Filtering::IgnoreDir
+                } else if entry.file_name() == "f1.txt" {
+                    Filtering::Ignore
+                } else {
+                    Filtering::Continue
+                }
+            });
+
+            let mut got = Vec::new();
+            while let Some(entry) = wd.next().await {
+                let entry = entry.unwrap();
+                got.push(entry.path());
+            }
+            got.sort();
+            assert_eq!(got, vec![d1, f2]);
+
+            Ok(())
+        })
+    }
+
+    /// A long run of consecutively skipped entries (below `min_depth`, or
+    /// pruned by `filter_entry`) used to recurse through `walk()` one async
+    /// stack frame per skip, overflowing the stack on large directories.
+    /// `walk()` now loops instead of recursing, so this must stay bounded.
+    const SKIP_REGRESSION_COUNT: usize = 20_000;
+
+    #[test]
+    fn walk_dir_min_depth_skips_many_entries_without_overflow() -> Result<()> {
+        block_on(async {
+            let root = tempfile::tempdir()?;
+            for i in 0..SKIP_REGRESSION_COUNT {
+                async_fs::write(root.path().join(format!("f{i}.txt")), []).await?;
+            }
+
+            let mut wd = WalkDir::new(root.path()).min_depth(2);
+
+            let mut count = 0;
+            while let Some(entry) = wd.next().await {
+                entry.unwrap();
+                count += 1;
+            }
+            assert_eq!(count, 0);
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn walk_dir_filter_entry_skips_many_entries_without_overflow() -> Result<()> {
+        block_on(async {
+            let root = tempfile::tempdir()?;
+            for i in 0..SKIP_REGRESSION_COUNT {
+                async_fs::write(root.path().join(format!("f{i}.txt")), []).await?;
+            }
+
+            let mut wd = WalkDir::new(root.path()).filter_entry(|_| Filtering::Ignore);
+
+            let mut count = 0;
+            while let Some(entry) = wd.next().await {
+                entry.unwrap();
+                count += 1;
+            }
+            assert_eq!(count, 0);
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn walk_dir_sort_by() -> Result<()> {
+        block_on(async {
+            let root = tempfile::tempdir()?;
+            let f1 = root.path().join("b.txt");
+            let f2 = root.path().join("a.txt");
+            let f3 = root.path().join("c.txt");
+
+            async_fs::write(&f1, []).await?;
+            async_fs::write(&f2, []).await?;
+            async_fs::write(&f3, []).await?;
+
+            let mut wd =
+                WalkDir::new(root.path()).sort_by(|a, b| a.file_name().cmp(&b.file_name()));
+
+            let mut got = Vec::new();
+            while let Some(entry) = wd.next().await {
+                got.push(entry.unwrap().path());
+            }
+            assert_eq!(got, vec![f2, f1, f3]);
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn walk_dir_contents_first() -> Result<()> {
+        block_on(async {
+            let root = tempfile::tempdir()?;
+            let d1 = root.path().join("d1");
+            let f1 = d1.join("f1.txt");
+
+            async_fs::create_dir_all(&d1).await?;
+            async_fs::write(&f1, []).await?;
+
+            let mut wd = WalkDir::new(root.path()).contents_first(true);
+
+            let mut got = Vec::new();
+            while let Some(entry) = wd.next().await {
+                got.push(entry.unwrap().path());
+            }
+            assert_eq!(got, vec![f1, d1]);
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn walk_dir_buffer() -> Result<()> {
+        block_on(async {
+            let root = tempfile::tempdir()?;
+            let mut want = Vec::new();
+            for i in 0..50 {
+                let f = root.path().join(format!("f{i}.txt"));
+                async_fs::write(&f, []).await?;
+                want.push(f);
+            }
+            want.sort();
+
+            let mut wd = WalkDir::new(root.path()).buffer(3);
+
+            let mut got = Vec::new();
+            while let Some(entry) = wd.next().await {
+                got.push(entry.unwrap().path());
+            }
+            got.sort();
+            assert_eq!(got, want);
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn walk_dir_buffer_clamps_out_of_range_values() -> Result<()> {
+        block_on(async {
+            let root = tempfile::tempdir()?;
+            let f = root.path().join("f.txt");
+            async_fs::write(&f, []).await?;
+
+            for buffer in [0, usize::MAX] {
+                let mut wd = WalkDir::new(root.path()).buffer(buffer);
+                let mut got = Vec::new();
+                while let Some(entry) = wd.next().await {
+                    got.push(entry.unwrap().path());
+                }
+                assert_eq!(got, vec![f.clone()]);
+            }
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn walk_dir_same_file_system() -> Result<()> {
+        block_on(async {
+            let root = tempfile::tempdir()?;
+            let d1 = root.path().join("d1");
+            let f1 = d1.join("f1.txt");
+
+            async_fs::create_dir_all(&d1).await?;
+            async_fs::write(&f1, []).await?;
+
+            let mut wd = WalkDir::new(root.path()).same_file_system(true);
+
+            let mut got = Vec::new();
+            while let Some(entry) = wd.next().await {
+                let entry = entry.unwrap();
+                got.push(entry.path());
+            }
+            got.sort();
+            assert_eq!(got, vec![d1, f1]);
+
+            Ok(())
+        })
+    }
+
+    /// A [`FileSystem`] wrapping [`StdFs`] that counts how many directories
+    /// it opened, to verify a custom backend is actually consulted instead
+    /// of `WalkDir` hard-coding the real filesystem.
+    struct CountingFs {
+        inner: StdFs,
+        read_dir_calls: Arc<AtomicUsize>,
+    }
+
+    impl FileSystem for CountingFs {
+        fn read_dir(
+            &self,
+            path: PathBuf,
+            buffer_size: usize,
+        ) -> BoxedFut<std::io::Result<BoxDirStream>> {
+            self.read_dir_calls.fetch_add(1, Ordering::SeqCst);
+            self.inner.read_dir(path, buffer_size)
+        }
+
+        fn metadata(&self, path: PathBuf) -> BoxedFut<std::io::Result<Metadata>> {
+            self.inner.metadata(path)
+        }
+
+        fn canonicalize(&self, path: PathBuf) -> BoxedFut<std::io::Result<PathBuf>> {
+            self.inner.canonicalize(path)
+        }
+    }
+
+    #[test]
+    fn walk_dir_custom_file_system() -> Result<()> {
+        block_on(async {
+            let root = tempfile::tempdir()?;
+            let d1 = root.path().join("d1");
+            let f1 = d1.join("f1.txt");
+
+            async_fs::create_dir_all(&d1).await?;
+            async_fs::write(&f1, []).await?;
+
+            let read_dir_calls = Arc::new(AtomicUsize::new(0));
+            let fs = CountingFs {
+                inner: StdFs,
+                read_dir_calls: read_dir_calls.clone(),
+            };
+            let mut wd = WalkDir::new_with_fs(root.path(), fs);
+
+            let mut got = Vec::new();
+            while let Some(entry) = wd.next().await {
+                got.push(entry.unwrap().path());
+            }
+            got.sort();
+            assert_eq!(got, vec![d1, f1]);
+            assert_eq!(read_dir_calls.load(Ordering::SeqCst), 2);
+
+            Ok(())
+        })
+    }
+
+    /// A [`DirEntryOps`] implementation that knows nothing about [`StdFs`]
+    /// or its private `StdDirEntry`: its metadata and file type are carried
+    /// as plain values rather than looked up from `path`, so it never
+    /// touches disk to answer for an entry, proving a downstream crate
+    /// could write its own.
+    struct MemDirEntry {
+        path: PathBuf,
+        metadata: Metadata,
+        file_type: FileType,
+    }
+
+    impl DirEntryOps for MemDirEntry {
+        fn path(&self) -> PathBuf {
+            self.path.clone()
+        }
+
+        fn file_name(&self) -> std::ffi::OsString {
+            self.path.file_name().expect("non-root entry").to_owned()
+        }
+
+        fn metadata(&self) -> BoxedFut<std::io::Result<Metadata>> {
+            let metadata = self.metadata.clone();
+            async move { Ok(metadata) }.boxed()
+        }
+
+        fn file_type(&self) -> BoxedFut<std::io::Result<FileType>> {
+            let file_type = self.file_type;
+            async move { Ok(file_type) }.boxed()
+        }
+    }
+
+    /// A [`FileSystem`] whose directory listings come from an in-memory map
+    /// of children instead of `std::fs::read_dir`, the way a virtual or
+    /// remote backend would. `Metadata`/`FileType` have no public
+    /// constructors, so it resolves one stand-in of each kind up front and
+    /// hands out clones of those instead of reading the (synthetic, never
+    /// created on disk) paths it reports on.
+    struct MemFs {
+        tree: std::collections::HashMap<PathBuf, Vec<(PathBuf, bool)>>,
+        dir_metadata: Metadata,
+        dir_type: FileType,
+        file_metadata: Metadata,
+        file_type: FileType,
+    }
+
+    impl FileSystem for MemFs {
+        fn read_dir(
+            &self,
+            path: PathBuf,
+            _buffer_size: usize,
+        ) -> BoxedFut<std::io::Result<BoxDirStream>> {
+            let children = self.tree.get(&path).cloned().unwrap_or_default();
+            let dir_metadata = self.dir_metadata.clone();
+            let dir_type = self.dir_type;
+            let file_metadata = self.file_metadata.clone();
+            let file_type = self.file_type;
+            async move {
+                Ok(
+                    futures_lite::stream::iter(children.into_iter().map(move |(path, is_dir)| {
+                        let (metadata, file_type) = if is_dir {
+                            (dir_metadata.clone(), dir_type)
+                        } else {
+                            (file_metadata.clone(), file_type)
+                        };
+                        Ok(DirEntry::new(MemDirEntry {
+                            path,
+                            metadata,
+                            file_type,
+                        }))
+                    }))
+                    .boxed(),
+                )
+            }
+            .boxed()
+        }
+
+        fn metadata(&self, _path: PathBuf) -> BoxedFut<std::io::Result<Metadata>> {
+            let metadata = self.dir_metadata.clone();
+            async move { Ok(metadata) }.boxed()
+        }
+
+        fn canonicalize(&self, path: PathBuf) -> BoxedFut<std::io::Result<PathBuf>> {
+            async move { Ok(path) }.boxed()
+        }
+    }
+
+    #[test]
+    fn walk_dir_from_scratch_file_system() -> Result<()> {
+        block_on(async {
+            // A real file and directory exist only to resolve one Metadata
+            // and FileType of each kind, since the standard library has no
+            // public constructor for either; the tree actually being
+            // walked below (root/d1/f1.txt) is never created on disk.
+            let stand_ins = tempfile::tempdir()?;
+            let stand_in_dir = stand_ins.path().join("dir");
+            let stand_in_file = stand_ins.path().join("file");
+            async_fs::create_dir(&stand_in_dir).await?;
+            async_fs::write(&stand_in_file, []).await?;
+            let dir_metadata = async_fs::metadata(&stand_in_dir).await?;
+            let file_metadata = async_fs::metadata(&stand_in_file).await?;
+
+            let root = PathBuf::from("/mem/root");
+            let d1 = root.join("d1");
+            let f1 = d1.join("f1.txt");
+
+            let mut tree = std::collections::HashMap::new();
+            tree.insert(root.clone(), vec![(d1.clone(), true)]);
+            tree.insert(d1.clone(), vec![(f1.clone(), false)]);
+            let fs = MemFs {
+                tree,
+                dir_type: dir_metadata.file_type(),
+                dir_metadata,
+                file_type: file_metadata.file_type(),
+                file_metadata,
+            };
+
+            let mut wd = WalkDir::new_with_fs(&root, fs);
+
+            let mut got = Vec::new();
+            while let Some(entry) = wd.next().await {
+                got.push(entry.unwrap().path());
+            }
+            got.sort();
+            assert_eq!(got, vec![d1, f1]);
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn walk_dir_follow_links() -> Result<()> {
+        block_on(async {
+            let root = tempfile::tempdir()?;
+            let d1 = root.path().join("d1");
+            let f1 = d1.join("f1.txt");
+            let link = root.path().join("link");
+
+            async_fs::create_dir_all(&d1).await?;
+            async_fs::write(&f1, []).await?;
+            async_fs::unix::symlink(&d1, &link).await?;
+
+            let mut wd = WalkDir::new(root.path()).follow_links(true);
+
+            let mut got = Vec::new();
+            while let Some(entry) = wd.next().await {
+                let entry = entry.unwrap();
+                got.push(entry.path());
+            }
+            let want_link_f1 = link.join("f1.txt");
+            got.sort();
+            assert_eq!(got, vec![d1, f1, link, want_link_f1]);
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn walk_dir_follow_links_loop() -> Result<()> {
+        block_on(async {
+            let root = tempfile::tempdir()?;
+            let d1 = root.path().join("d1");
+            let link = d1.join("link");
+
+            async_fs::create_dir_all(&d1).await?;
+            async_fs::unix::symlink(root.path(), &link).await?;
+
+            let mut wd = WalkDir::new(root.path()).follow_links(true);
+
+            let mut saw_loop = false;
+            while let Some(entry) = wd.next().await {
+                if let Err(e) = entry {
+                    assert_eq!(e.path(), Some(link.as_path()));
+                    saw_loop = true;
+                }
+            }
+            assert!(saw_loop);
+
+            Ok(())
+        })
+    }
 }